@@ -0,0 +1 @@
+pub const PEER_ID: &str = "00112233445566778899";
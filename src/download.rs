@@ -0,0 +1,107 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{Seek, SeekFrom, Write},
+    path::Path,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use crate::{error::TorrentError, tfile::TorrentFile};
+
+/// Downloads every piece of `file`, spreading the work across all peers
+/// returned by `find_peers` (each on its own pipelined connection), then
+/// reassembles the pieces in index order and writes them to `output`.
+///
+/// For single-file torrents `output` is the destination file path; for
+/// multi-file torrents it's the directory under which the torrent's
+/// `name/` tree is created.
+pub fn download(file: &mut TorrentFile, output: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let n_pieces = file.info.n_pieces();
+    let peers: Vec<_> = file.find_peers()?.collect();
+
+    let queue = Arc::new(Mutex::new((0..n_pieces as u32).collect::<VecDeque<u32>>()));
+    let (tx, rx) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for peer in &peers {
+            let queue = Arc::clone(&queue);
+            let tx = tx.clone();
+
+            scope.spawn(move || {
+                let Ok(mut connection) = peer.handshake() else {
+                    return;
+                };
+
+                loop {
+                    let Some(index) = queue.lock().unwrap().pop_front() else {
+                        break;
+                    };
+
+                    let mut piece_data = vec![];
+                    match connection.download_piece(index, &mut piece_data) {
+                        Ok(()) => tx.send((index, piece_data)).unwrap(),
+                        Err(_) => {
+                            // Requeue for another attempt by a different
+                            // peer, then stop using this connection: it just
+                            // failed once already (disconnect, choke, bad
+                            // hash, garbage data), so retrying on it offers
+                            // no new information and risks looping forever
+                            // on a peer that fails instantly every time.
+                            queue.lock().unwrap().push_back(index);
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    drop(tx);
+
+    let mut pieces: Vec<Option<Vec<u8>>> = vec![None; n_pieces];
+    for (index, data) in rx {
+        pieces[index as usize] = Some(data);
+    }
+
+    let missing = pieces.iter().filter(|p| p.is_none()).count();
+    if missing > 0 {
+        return Err(Box::new(TorrentError::Incomplete { missing }));
+    }
+
+    let pieces: Vec<Vec<u8>> = pieces.into_iter().map(Option::unwrap).collect();
+    write_output(file, output, &pieces);
+    Ok(())
+}
+
+fn write_output(file: &TorrentFile, output: &str, pieces: &[Vec<u8>]) {
+    if file.info.files.is_none() {
+        let mut out = File::create(output).unwrap();
+        for piece in pieces {
+            out.write_all(piece).unwrap();
+        }
+        return;
+    }
+
+    let plength = file.info.plength;
+    for (index, piece) in pieces.iter().enumerate() {
+        let global_offset = index * plength;
+        let mut cursor = 0usize;
+
+        for (rel_path, file_offset, length) in file.info.map_offset(global_offset, piece.len()) {
+            let path = Path::new(output).join(rel_path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).unwrap();
+            }
+
+            let mut out = fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .open(&path)
+                .unwrap();
+            out.seek(SeekFrom::Start(file_offset as u64)).unwrap();
+            out.write_all(&piece[cursor..cursor + length]).unwrap();
+
+            cursor += length;
+        }
+    }
+}
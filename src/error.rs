@@ -0,0 +1,91 @@
+use std::{fmt, io};
+
+/// Crate-wide error type for the parsing and peer-wire paths. Tracker
+/// announces report failures through [`crate::tracker::TrackerError`].
+#[derive(Debug)]
+pub enum TorrentError {
+    Io(io::Error),
+    Bencode(serde_bencode::Error),
+    /// The info hash the peer echoed back during the handshake doesn't
+    /// match the one we sent.
+    HandshakeMismatch {
+        expected: [u8; 20],
+        actual: [u8; 20],
+    },
+    /// A downloaded piece's SHA-1 doesn't match the one in the torrent's
+    /// `pieces` list.
+    HashMismatch {
+        piece_index: u32,
+    },
+    /// Got a well-formed but out-of-sequence peer message, e.g. a `Piece`
+    /// where a `Bitfield` was expected.
+    UnexpectedMessage(&'static str),
+    /// A `magnet:?...` URI was missing its `xt` parameter, or `xt` wasn't a
+    /// valid `urn:btih:<40-char hex info hash>`.
+    InvalidMagnet,
+    /// A peer's `Piece` message carried an index other than the one we
+    /// requested.
+    UnexpectedPieceIndex { expected: u32, actual: u32 },
+    /// A peer's `Piece` message's `begin`/block length would write past the
+    /// end of the piece buffer.
+    BlockOutOfRange {
+        piece_index: u32,
+        begin: u32,
+        length: usize,
+    },
+    /// `download()` gave up with some pieces never successfully received
+    /// from any peer.
+    Incomplete { missing: usize },
+}
+
+impl fmt::Display for TorrentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TorrentError::Io(e) => write!(f, "I/O error: {e}"),
+            TorrentError::Bencode(e) => write!(f, "bencode error: {e}"),
+            TorrentError::HandshakeMismatch { expected, actual } => write!(
+                f,
+                "peer handshake returned a mismatched info hash: expected {}, got {}",
+                hex::encode(expected),
+                hex::encode(actual)
+            ),
+            TorrentError::HashMismatch { piece_index } => {
+                write!(f, "piece {piece_index} failed SHA-1 verification")
+            }
+            TorrentError::UnexpectedMessage(expected) => {
+                write!(f, "expected a {expected} message from peer")
+            }
+            TorrentError::InvalidMagnet => {
+                write!(f, "magnet link is missing a valid xt=urn:btih:<info hash> parameter")
+            }
+            TorrentError::UnexpectedPieceIndex { expected, actual } => {
+                write!(f, "expected a piece for index {expected}, got index {actual}")
+            }
+            TorrentError::BlockOutOfRange {
+                piece_index,
+                begin,
+                length,
+            } => write!(
+                f,
+                "piece {piece_index} got a block at offset {begin} of length {length} that overruns the piece"
+            ),
+            TorrentError::Incomplete { missing } => {
+                write!(f, "download incomplete: {missing} piece(s) were never received")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TorrentError {}
+
+impl From<io::Error> for TorrentError {
+    fn from(e: io::Error) -> Self {
+        TorrentError::Io(e)
+    }
+}
+
+impl From<serde_bencode::Error> for TorrentError {
+    fn from(e: serde_bencode::Error) -> Self {
+        TorrentError::Bencode(e)
+    }
+}
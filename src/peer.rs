@@ -1,14 +1,18 @@
 use std::{
-    io::{Cursor, Read, Write},
+    io::{Read, Write},
     net::{SocketAddrV4, TcpStream},
 };
 
 use sha1::{Digest, Sha1};
 
-use crate::{consts::PEER_ID, tfile::TorrentFile};
+use crate::{consts::PEER_ID, error::TorrentError, tfile::TorrentFile};
 
 const PROTOCOL_STRING: &str = "BitTorrent protocol";
-const BLOCK_SIZE: u32 = 1 << 14;
+const BLOCK_SIZE: u32 = crate::tfile::BLOCK_SIZE as u32;
+/// Maximum number of `Request` messages kept in flight at once. Pipelining
+/// requests like this (instead of waiting for each `Piece` reply before
+/// sending the next `Request`) is what makes single-peer throughput usable.
+const PIPELINE_DEPTH: u32 = 5;
 
 pub struct Peer<'a> {
     pub addr: SocketAddrV4,
@@ -20,38 +24,46 @@ impl<'a> Peer<'a> {
         Self { addr, file }
     }
 
-    pub fn handshake(&self) -> PeerConnection {
-        let mut stream = TcpStream::connect(self.addr).unwrap();
+    pub fn handshake(&self) -> Result<PeerConnection, TorrentError> {
+        let mut stream = TcpStream::connect(self.addr)?;
 
-        stream.write(&[PROTOCOL_STRING.len() as u8]).unwrap();
-        stream.write(PROTOCOL_STRING.as_bytes()).unwrap();
-        stream.write(&[0; 8]).unwrap();
-        stream.write(&self.file.info.hash()).unwrap();
-        stream.write(PEER_ID.as_bytes()).unwrap();
-        stream.flush().unwrap();
+        stream.write(&[PROTOCOL_STRING.len() as u8])?;
+        stream.write(PROTOCOL_STRING.as_bytes())?;
+        stream.write(&[0; 8])?;
+        stream.write(&self.file.info_hash())?;
+        stream.write(PEER_ID.as_bytes())?;
+        stream.flush()?;
 
         let mut n_pstring = [0u8];
-        stream.read_exact(&mut n_pstring).unwrap();
+        stream.read_exact(&mut n_pstring)?;
         let mut pstring = vec![0u8; n_pstring[0] as usize];
-        stream.read_exact(&mut pstring).unwrap();
+        stream.read_exact(&mut pstring)?;
 
         // eight reserved bytes, which are all set to zero (8 bytes)
-        std::io::copy(&mut Read::by_ref(&mut stream).take(8), &mut std::io::sink()).unwrap();
+        std::io::copy(&mut Read::by_ref(&mut stream).take(8), &mut std::io::sink())?;
 
         let mut info_hash = [0u8; 20];
-        stream.read_exact(&mut info_hash).unwrap();
+        stream.read_exact(&mut info_hash)?;
+
+        let expected = self.file.info_hash();
+        if info_hash != expected {
+            return Err(TorrentError::HandshakeMismatch {
+                expected,
+                actual: info_hash,
+            });
+        }
 
         let mut peer_id = [0u8; 20];
-        stream.read_exact(&mut peer_id).unwrap();
+        stream.read_exact(&mut peer_id)?;
 
-        PeerConnection {
+        Ok(PeerConnection {
             protocol: pstring,
             info_hash,
             peer_id,
             stream,
             peer: self,
             initiated: false,
-        }
+        })
     }
 }
 
@@ -66,130 +78,146 @@ pub struct PeerConnection<'a> {
 }
 
 impl<'a> PeerConnection<'a> {
-    fn receive_message(&mut self) -> PeerMessage {
-        // println!("receiving");
-        let mut header = [0u8; 4];
-        self.stream.read_exact(&mut header).unwrap();
-
-        let mut length = Self::u32_from_bytes(&header);
-
-        // println!("received length: {0}", length);
-
-        if length == 0 {
-            // Messages of length zero are keepalives, and ignored. Keepalives are generally
-            // sent once every two minutes, but note that timeouts can be done much more
-            // quickly when data is expected.
-            return self.receive_message();
-        }
+    fn receive_message(&mut self) -> Result<PeerMessage, TorrentError> {
+        loop {
+            let mut header = [0u8; 4];
+            self.stream.read_exact(&mut header)?;
+
+            let mut length = Self::u32_from_bytes(&header);
+
+            if length == 0 {
+                // Messages of length zero are keepalives, and ignored. Keepalives are generally
+                // sent once every two minutes, but note that timeouts can be done much more
+                // quickly when data is expected.
+                continue;
+            }
 
-        length -= 1;
-
-        // println!("received id: {0}", length);
-
-        let mut id = [0u8];
-        self.stream.read_exact(&mut id).unwrap();
-        let id = id[0];
-
-        // println!("received id: {0}", id);
-
-        let mut payload = vec![0u8; length as usize];
-        self.stream.read_exact(&mut payload).unwrap();
-        match id {
-            5 => PeerMessage::Bitfield,
-            2 => PeerMessage::Interested,
-            1 => PeerMessage::Unchoke,
-            6 => PeerMessage::Request(RequestPayload {
-                index: Self::u32_from_bytes(&payload),
-                begin: Self::u32_from_bytes(&payload[4..]),
-                length: Self::u32_from_bytes(&payload[8..]),
-            }),
-            7 => PeerMessage::Piece(PiecePayload {
-                index: Self::u32_from_bytes(&payload),
-                begin: Self::u32_from_bytes(&payload[4..]),
-                block: {
-                    payload.drain(0..8);
-                    payload
-                },
-            }),
-            _ => self.receive_message(),
+            length -= 1;
+
+            let mut id = [0u8];
+            self.stream.read_exact(&mut id)?;
+            let id = id[0];
+
+            let mut payload = vec![0u8; length as usize];
+            self.stream.read_exact(&mut payload)?;
+
+            return Ok(match id {
+                5 => PeerMessage::Bitfield,
+                2 => PeerMessage::Interested,
+                1 => PeerMessage::Unchoke,
+                6 => PeerMessage::Request(RequestPayload {
+                    index: Self::u32_from_bytes(&payload),
+                    begin: Self::u32_from_bytes(&payload[4..]),
+                    length: Self::u32_from_bytes(&payload[8..]),
+                }),
+                7 => PeerMessage::Piece(PiecePayload {
+                    index: Self::u32_from_bytes(&payload),
+                    begin: Self::u32_from_bytes(&payload[4..]),
+                    block: {
+                        payload.drain(0..8);
+                        payload
+                    },
+                }),
+                // Unknown/unsupported message ids are simply skipped.
+                _ => continue,
+            });
         }
     }
 
-    fn send_message(&mut self, message: PeerMessage) {
+    fn send_message(&mut self, message: PeerMessage) -> Result<(), TorrentError> {
         match message {
             PeerMessage::Interested => {
                 // length
-                self.stream.write(&1u32.to_be_bytes()).unwrap();
+                self.stream.write(&1u32.to_be_bytes())?;
                 // id
-                self.stream.write(&[2u8]).unwrap();
+                self.stream.write(&[2u8])?;
             }
             PeerMessage::Request(payload) => {
                 // length
-                self.stream.write(&13u32.to_be_bytes()).unwrap();
+                self.stream.write(&13u32.to_be_bytes())?;
                 // id
-                self.stream.write(&[6u8]).unwrap();
-                self.stream.write(&payload.index.to_be_bytes()).unwrap();
-                self.stream.write(&payload.begin.to_be_bytes()).unwrap();
-                self.stream.write(&payload.length.to_be_bytes()).unwrap();
+                self.stream.write(&[6u8])?;
+                self.stream.write(&payload.index.to_be_bytes())?;
+                self.stream.write(&payload.begin.to_be_bytes())?;
+                self.stream.write(&payload.length.to_be_bytes())?;
             }
-            _ => panic!("Unabled to send the message"),
+            _ => unreachable!("only Interested and Request are ever sent"),
         };
 
-        self.stream.flush().unwrap();
+        self.stream.flush()?;
+        Ok(())
     }
 
-    pub fn download_piece<W>(&mut self, index: u32, output: &mut W)
+    pub fn download_piece<W>(&mut self, index: u32, output: &mut W) -> Result<(), TorrentError>
     where
         W: Write,
     {
         if !self.initiated {
-            let PeerMessage::Bitfield = self.receive_message() else {
-                panic!("Didn't receive the bitfield message")
+            let PeerMessage::Bitfield = self.receive_message()? else {
+                return Err(TorrentError::UnexpectedMessage("bitfield"));
             };
 
-            self.send_message(PeerMessage::Interested);
+            self.send_message(PeerMessage::Interested)?;
 
-            let PeerMessage::Unchoke = self.receive_message() else {
-                panic!("Didn't receive the unchoke message")
+            let PeerMessage::Unchoke = self.receive_message()? else {
+                return Err(TorrentError::UnexpectedMessage("unchoke"));
             };
 
             self.initiated = true;
         }
 
-        let plength = self.peer.file.info.nth_plength(index as usize) as u32;
+        let plength = self.peer.file.info.nth_plength(index as usize);
+        let n_blocks = self.peer.file.info.blocks_in_piece(index as usize);
 
-        let mut begin = 0u32;
+        let mut piece_data = vec![0u8; plength];
+        let mut next_block = 0usize;
+        let mut in_flight = 0u32;
+        let mut received_blocks = 0usize;
 
-        let mut piece_data = Vec::<u8>::with_capacity(plength as usize);
+        while received_blocks < n_blocks {
+            while in_flight < PIPELINE_DEPTH && next_block < n_blocks {
+                let begin = next_block * BLOCK_SIZE as usize;
+                let length = self.peer.file.info.block_length(index as usize, next_block) as u32;
 
-        while begin < plength {
-            let length = if begin + BLOCK_SIZE < plength {
-                BLOCK_SIZE
-            } else {
-                plength - begin
-            };
-
-            // println!("Downloading: {}, {}, {}", index, begin, length);
+                self.send_message(PeerMessage::Request(RequestPayload {
+                    index,
+                    begin: begin as u32,
+                    length,
+                }))?;
 
-            self.send_message(PeerMessage::Request(RequestPayload {
-                index,
-                begin,
-                length,
-            }));
+                next_block += 1;
+                in_flight += 1;
+            }
 
-            let PeerMessage::Piece(payload) = self.receive_message() else {
-                panic!("Didn't receive the piece message")
+            let PeerMessage::Piece(payload) = self.receive_message()? else {
+                return Err(TorrentError::UnexpectedMessage("piece"));
             };
 
-            let mut block_data = Cursor::new(payload.block);
-            std::io::copy(&mut block_data, &mut piece_data).unwrap();
+            if payload.index != index {
+                return Err(TorrentError::UnexpectedPieceIndex {
+                    expected: index,
+                    actual: payload.index,
+                });
+            }
 
-            begin += length;
+            let begin = payload.begin as usize;
+            let end = begin
+                .checked_add(payload.block.len())
+                .filter(|&end| end <= piece_data.len())
+                .ok_or(TorrentError::BlockOutOfRange {
+                    piece_index: index,
+                    begin: payload.begin,
+                    length: payload.block.len(),
+                })?;
+            piece_data[begin..end].copy_from_slice(&payload.block);
+
+            received_blocks += 1;
+            in_flight -= 1;
         }
 
-        if begin == 0 {
+        if plength == 0 {
             // in case index >= number of the pieces
-            return;
+            return Ok(());
         }
 
         let mut hasher = Sha1::new();
@@ -197,17 +225,12 @@ impl<'a> PeerConnection<'a> {
         let computed_hash = hasher.finalize();
         let piece_hash = self.peer.file.info.pieces().nth(index as usize).unwrap();
 
-        if computed_hash.len() != piece_hash.len() {
-            panic!("Hash mismatch");
-        }
-
-        for i in 0..computed_hash.len() {
-            if computed_hash[i] != piece_hash[i] {
-                panic!("Hash mismatch");
-            }
+        if computed_hash.as_slice() != piece_hash {
+            return Err(TorrentError::HashMismatch { piece_index: index });
         }
 
-        output.write_all(&piece_data).unwrap();
+        output.write_all(&piece_data)?;
+        Ok(())
     }
 
     fn u32_from_bytes(data: &[u8]) -> u32 {
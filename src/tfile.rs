@@ -1,62 +1,215 @@
 use std::{
     fs,
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{SocketAddr, SocketAddrV4, ToSocketAddrs},
+    path::PathBuf,
 };
 
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 
-use crate::{consts::PEER_ID, peer::Peer};
+use crate::{
+    consts::PEER_ID,
+    error::TorrentError,
+    peer::Peer,
+    tracker::{TrackerError, TrackerRequest},
+};
+
+/// Standard BitTorrent block size used when splitting a piece into the
+/// `request`/`piece` messages of the peer wire protocol.
+pub(crate) const BLOCK_SIZE: usize = 1 << 14;
 
 #[derive(Serialize, Deserialize)]
 pub struct TorrentFile {
-    pub announce: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub announce: Option<String>,
+    #[serde(rename = "announce-list", skip_serializing_if = "Option::is_none")]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<Vec<(String, u16)>>,
     pub info: TorrentFileInfo,
+    /// Set only for torrents built from a magnet link, whose info hash is
+    /// known up front (from `xt`) but whose `info` dict is a placeholder
+    /// until a future metadata exchange (BEP 9) fills it in.
+    #[serde(skip)]
+    info_hash_override: Option<[u8; 20]>,
 }
 
 impl TorrentFile {
-    pub fn from_file(path: &str) -> Self {
-        let bytes = fs::read(path).unwrap();
-        return serde_bencode::from_bytes(&bytes).unwrap();
+    pub fn from_file(path: &str) -> Result<Self, TorrentError> {
+        let bytes = fs::read(path)?;
+        Ok(serde_bencode::from_bytes(&bytes)?)
+    }
+
+    /// Parses a `magnet:?xt=urn:btih:<info hash>&dn=<name>&tr=<tracker>...`
+    /// URI. Since a magnet link carries no piece data, the resulting
+    /// `info` is a placeholder that lets the existing tracker/peer
+    /// pipeline locate peers by info hash; downloading from those peers
+    /// still requires the metadata (BEP 9) exchange this crate doesn't
+    /// implement yet.
+    pub fn from_magnet(uri: &str) -> Result<Self, TorrentError> {
+        let query = uri.strip_prefix("magnet:?").ok_or(TorrentError::InvalidMagnet)?;
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).map_err(|_| TorrentError::InvalidMagnet)?;
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = vec![];
+
+        for (key, value) in &params {
+            match key.as_str() {
+                "xt" => {
+                    let hex_hash = value.strip_prefix("urn:btih:").ok_or(TorrentError::InvalidMagnet)?;
+                    let bytes = hex::decode(hex_hash).map_err(|_| TorrentError::InvalidMagnet)?;
+                    info_hash = Some(<[u8; 20]>::try_from(bytes.as_slice()).map_err(|_| TorrentError::InvalidMagnet)?);
+                }
+                "dn" => name = Some(value.clone()),
+                "tr" => trackers.push(value.clone()),
+                _ => {}
+            }
+        }
+
+        let info_hash = info_hash.ok_or(TorrentError::InvalidMagnet)?;
+
+        // Keep every tr= tracker: a single one becomes `announce`, multiple
+        // form a one-tier `announce-list` so `find_peers` tries them all
+        // instead of silently dropping all but the first.
+        let (announce, announce_list) = match trackers.len() {
+            0 => (None, None),
+            1 => (trackers.into_iter().next(), None),
+            _ => (None, Some(vec![trackers])),
+        };
+
+        Ok(TorrentFile {
+            announce,
+            announce_list,
+            nodes: None,
+            info: TorrentFileInfo {
+                length: Some(0),
+                name: name.unwrap_or_else(|| hex::encode(info_hash)),
+                plength: 0,
+                pieces_data: vec![],
+                files: None,
+            },
+            info_hash_override: Some(info_hash),
+        })
     }
 
     pub fn create_peer(&self, addr: SocketAddrV4) -> Peer {
         Peer::new(addr, self)
     }
 
-    pub fn find_peers(&self) -> impl Iterator<Item = Peer> {
+    /// The torrent's info hash: the `xt` hash given on a magnet link, or
+    /// the SHA-1 of the bencoded `info` dict otherwise.
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash_override.unwrap_or_else(|| self.info.hash())
+    }
+
+    pub fn find_peers(&mut self) -> Result<impl Iterator<Item = Peer>, TrackerError> {
         let req = TrackerRequest {
-            info_hash: self.info.hash(),
+            info_hash: self.info_hash(),
             peer_id: PEER_ID.into(),
             port: 6881,
             uploaded: 0,
             downloaded: 0,
-            left: self.info.length,
+            left: self.info.total_length(),
             compact: true,
         };
 
-        let url = req.create_url(&self.announce);
+        let peers = match (&mut self.announce_list, &self.announce) {
+            (Some(tiers), _) => Self::announce_tiers(&req, tiers),
+            (None, Some(announce)) => match req.announce(announce) {
+                Ok(peers) => peers,
+                Err(_) if self.nodes.is_some() => vec![],
+                Err(e) => return Err(e),
+            },
+            (None, None) => vec![],
+        };
 
-        let client = reqwest::blocking::Client::new();
-        let http_response = client.get(url).send().unwrap();
+        // Trackerless torrents (and torrents whose trackers are all down)
+        // fall back to the DHT bootstrap nodes shipped in the metainfo.
+        let peers = if peers.is_empty() {
+            self.bootstrap_nodes()
+        } else {
+            peers
+        };
 
-        let resp: TrackerResponse =
-            serde_bencode::from_bytes(&http_response.bytes().unwrap()).unwrap();
+        if peers.is_empty() {
+            return Err(TrackerError::NoResponse);
+        }
+
+        let file: &TorrentFile = &*self;
+        Ok(peers.into_iter().map(move |addr| Peer::new(addr, file)))
+    }
+
+    /// Resolves the DHT `nodes` list into bootstrap peer addresses. This is
+    /// a first step towards a full DHT subsystem: today these nodes are
+    /// just handed to the peer pipeline directly rather than queried for
+    /// `get_peers`.
+    fn bootstrap_nodes(&self) -> Vec<SocketAddrV4> {
+        let Some(nodes) = &self.nodes else {
+            return vec![];
+        };
 
-        resp.get_peers()
-            .into_iter()
-            .map(move |addr| Peer::new(addr, &self))
+        nodes
+            .iter()
+            .filter_map(|(host, port)| (host.as_str(), *port).to_socket_addrs().ok())
+            .filter_map(|mut addrs| {
+                addrs.find_map(|addr| match addr {
+                    SocketAddr::V4(v4) => Some(v4),
+                    SocketAddr::V6(_) => None,
+                })
+            })
+            .collect()
+    }
+
+    /// Tries each tracker of each tier in order, moving to the next tier
+    /// only once every tracker in the current one has failed to return
+    /// peers. Each tier is shuffled before use, and the tracker that
+    /// responds is swapped to the front of its tier. Since every call
+    /// reshuffles each tier first, that order does not carry over to later
+    /// announces.
+    fn announce_tiers(req: &TrackerRequest, tiers: &mut [Vec<String>]) -> Vec<SocketAddrV4> {
+        for tier in tiers.iter_mut() {
+            Self::shuffle_tier(tier);
+
+            for i in 0..tier.len() {
+                if let Ok(peers) = req.announce(&tier[i]) {
+                    if !peers.is_empty() {
+                        tier.swap(0, i);
+                        return peers;
+                    }
+                }
+            }
+        }
+
+        vec![]
+    }
+
+    fn shuffle_tier(tier: &mut [String]) {
+        for i in (1..tier.len()).rev() {
+            let j = crate::tracker::random_u32() as usize % (i + 1);
+            tier.swap(i, j);
+        }
     }
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct TorrentFileInfo {
+pub struct FileEntry {
     pub length: usize,
+    pub path: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TorrentFileInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
     pub name: String,
     #[serde(rename = "piece length")]
     pub plength: usize,
     #[serde(rename = "pieces", with = "serde_bytes")]
     pieces_data: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileEntry>>,
 }
 
 impl TorrentFileInfo {
@@ -75,81 +228,162 @@ impl TorrentFileInfo {
         self.pieces_data.len() / 20
     }
 
+    /// Total size of the torrent's contents: the sum of all file lengths in
+    /// multi-file mode, or the single top-level `length` otherwise.
+    pub fn total_length(&self) -> usize {
+        match &self.files {
+            Some(files) => files.iter().map(|f| f.length).sum(),
+            None => self
+                .length
+                .expect("single-file torrent info must specify a length"),
+        }
+    }
+
     pub fn nth_plength(&self, n: usize) -> usize {
         let n_pieces = self.n_pieces();
+        if n_pieces == 0 {
+            // Magnet-link placeholder info before the BEP 9 metadata
+            // exchange fills in real piece data.
+            return 0;
+        }
         if n < n_pieces - 1 {
             self.plength
         } else if n == (n_pieces - 1) {
-            self.length % self.plength
+            self.total_length() - self.plength * (n_pieces - 1)
         } else {
             0
         }
     }
-}
 
-#[derive(Serialize)]
-struct TrackerRequest {
-    #[serde(skip_serializing)]
-    info_hash: [u8; 20],
-    peer_id: String,
-    port: u16,
-    uploaded: usize,
-    downloaded: usize,
-    left: usize,
-    #[serde(serialize_with = "bool_to_u8")]
-    compact: bool,
-}
+    /// Number of `2^14`-byte blocks a `request`/`piece` exchange splits the
+    /// given piece into, accounting for its possibly-short final block.
+    pub fn blocks_in_piece(&self, piece_index: usize) -> usize {
+        self.nth_plength(piece_index).div_ceil(BLOCK_SIZE)
+    }
 
-fn bool_to_u8<S>(b: &bool, s: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    s.serialize_u8(if *b { 1 } else { 0 })
-}
+    /// Length in bytes of a single block within a piece, short only for the
+    /// final block of a piece whose length isn't a multiple of the block
+    /// size (such as the final, possibly short, piece).
+    pub fn block_length(&self, piece_index: usize, block_index: usize) -> usize {
+        let plength = self.nth_plength(piece_index);
+        let begin = block_index * BLOCK_SIZE;
+        usize::min(BLOCK_SIZE, plength.saturating_sub(begin))
+    }
 
-impl TrackerRequest {
-    pub fn create_url(&self, tracker_url: &str) -> String {
-        let params = serde_urlencoded::to_string(&self).unwrap();
+    /// Locates the `(file_index, offset_within_file)` that a global byte
+    /// offset into the torrent's logical, contiguous data falls into.
+    /// Only meaningful in multi-file mode.
+    fn locate(&self, offset: usize) -> (usize, usize) {
+        let files = self
+            .files
+            .as_ref()
+            .expect("locate is only meaningful for multi-file torrents");
 
-        format!(
-            "{}?{}&info_hash={}",
-            tracker_url,
-            params,
-            Self::urlencode_bytes(&self.info_hash)
-        )
+        let mut file_start = 0usize;
+        for (file_index, file) in files.iter().enumerate() {
+            if offset < file_start + file.length {
+                return (file_index, offset - file_start);
+            }
+            file_start += file.length;
+        }
+
+        panic!("offset {offset} is past the end of the torrent");
     }
 
-    fn urlencode_bytes(bytes: &[u8; 20]) -> String {
-        let mut encoded = String::with_capacity(3 * bytes.len());
-        for &b in bytes {
-            encoded.push('%');
-            encoded.push_str(&hex::encode(&[b]));
+    /// Maps a byte range `[offset, offset + len)` of the torrent's logical,
+    /// contiguous data to the output file(s) it spans, as
+    /// `(relative path, offset within that file, length)` triples. In
+    /// single-file mode this is always a single triple against `name`.
+    pub fn map_offset(&self, offset: usize, len: usize) -> Vec<(PathBuf, usize, usize)> {
+        let Some(files) = &self.files else {
+            return vec![(PathBuf::from(&self.name), offset, len)];
+        };
+
+        let (start_file_index, mut offset_in_file) = self.locate(offset);
+
+        let mut slices = vec![];
+        let mut remaining = len;
+
+        for file in &files[start_file_index..] {
+            if remaining == 0 {
+                break;
+            }
+
+            let take = usize::min(remaining, file.length - offset_in_file);
+
+            let mut path = PathBuf::from(&self.name);
+            path.extend(&file.path);
+            slices.push((path, offset_in_file, take));
+
+            remaining -= take;
+            offset_in_file = 0;
         }
 
-        encoded
+        slices
     }
 }
 
-#[derive(Deserialize)]
-#[allow(dead_code)]
-struct TrackerResponse {
-    interval: u32,
-    #[serde(rename = "peers", with = "serde_bytes")]
-    peers_bytes: Vec<u8>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl TrackerResponse {
-    fn get_peers(&self) -> Vec<SocketAddrV4> {
-        let mut peers = vec![];
-        let n_peers = self.peers_bytes.len() / 6;
-        for i in 0..n_peers {
-            let p = &self.peers_bytes[i * 6..];
-            peers.push(SocketAddrV4::new(
-                Ipv4Addr::new(p[0], p[1], p[2], p[3]),
-                u16::from_be_bytes([p[4], p[5]]),
-            ));
+    fn single_file_info(length: usize, plength: usize, n_pieces: usize) -> TorrentFileInfo {
+        TorrentFileInfo {
+            length: Some(length),
+            name: "test".into(),
+            plength,
+            pieces_data: vec![0u8; n_pieces * 20],
+            files: None,
         }
+    }
+
+    #[test]
+    fn nth_plength_is_full_when_total_length_divides_evenly() {
+        let info = single_file_info(40, 20, 2);
+        assert_eq!(info.nth_plength(0), 20);
+        assert_eq!(info.nth_plength(1), 20);
+    }
+
+    #[test]
+    fn nth_plength_shortens_the_last_piece_when_it_does_not_divide_evenly() {
+        let info = single_file_info(45, 20, 3);
+        assert_eq!(info.nth_plength(0), 20);
+        assert_eq!(info.nth_plength(1), 20);
+        assert_eq!(info.nth_plength(2), 5);
+    }
+
+    #[test]
+    fn nth_plength_is_zero_for_the_metadata_less_magnet_placeholder() {
+        let info = single_file_info(0, 0, 0);
+        assert_eq!(info.nth_plength(0), 0);
+    }
+
+    #[test]
+    fn map_offset_spans_a_file_boundary() {
+        let info = TorrentFileInfo {
+            length: None,
+            name: "torrent".into(),
+            plength: 10,
+            pieces_data: vec![0u8; 20],
+            files: Some(vec![
+                FileEntry {
+                    length: 5,
+                    path: vec!["a.txt".into()],
+                },
+                FileEntry {
+                    length: 10,
+                    path: vec!["b.txt".into()],
+                },
+            ]),
+        };
 
-        peers
+        let slices = info.map_offset(3, 6);
+        assert_eq!(
+            slices,
+            vec![
+                (PathBuf::from("torrent/a.txt"), 3, 2),
+                (PathBuf::from("torrent/b.txt"), 0, 4),
+            ]
+        );
     }
 }
@@ -0,0 +1,279 @@
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, SocketAddrV4, ToSocketAddrs, UdpSocket},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize, Serializer};
+
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+const UDP_ACTION_CONNECT: u32 = 0;
+const UDP_ACTION_ANNOUNCE: u32 = 1;
+/// BEP 15 retransmission attempts: timeout for attempt `n` is `15 * 2^n`
+/// seconds, up to `n = 8` (64 minutes on the last attempt).
+const UDP_MAX_RETRIES: u32 = 8;
+
+/// Errors reaching or talking to a tracker, as distinct from the torrent
+/// file/peer-wire errors in [`crate::error::TorrentError`].
+#[derive(Debug)]
+pub enum TrackerError {
+    Http(reqwest::Error),
+    Bencode(serde_bencode::Error),
+    UrlEncode(serde_urlencoded::ser::Error),
+    Io(io::Error),
+    AddrResolve(String),
+    /// A UDP tracker never answered after exhausting the BEP 15 retries, or
+    /// no tracker or bootstrap node yielded any peer at all.
+    NoResponse,
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrackerError::Http(e) => write!(f, "tracker HTTP request failed: {e}"),
+            TrackerError::Bencode(e) => write!(f, "tracker response wasn't valid bencode: {e}"),
+            TrackerError::UrlEncode(e) => write!(f, "failed to encode tracker request: {e}"),
+            TrackerError::Io(e) => write!(f, "tracker I/O error: {e}"),
+            TrackerError::AddrResolve(host) => write!(f, "could not resolve tracker address: {host}"),
+            TrackerError::NoResponse => write!(f, "tracker did not respond"),
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {}
+
+impl From<reqwest::Error> for TrackerError {
+    fn from(e: reqwest::Error) -> Self {
+        TrackerError::Http(e)
+    }
+}
+
+impl From<serde_bencode::Error> for TrackerError {
+    fn from(e: serde_bencode::Error) -> Self {
+        TrackerError::Bencode(e)
+    }
+}
+
+impl From<serde_urlencoded::ser::Error> for TrackerError {
+    fn from(e: serde_urlencoded::ser::Error) -> Self {
+        TrackerError::UrlEncode(e)
+    }
+}
+
+impl From<io::Error> for TrackerError {
+    fn from(e: io::Error) -> Self {
+        TrackerError::Io(e)
+    }
+}
+
+#[derive(Serialize)]
+pub struct TrackerRequest {
+    #[serde(skip_serializing)]
+    pub info_hash: [u8; 20],
+    pub peer_id: String,
+    pub port: u16,
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub left: usize,
+    #[serde(serialize_with = "bool_to_u8")]
+    pub compact: bool,
+}
+
+fn bool_to_u8<S>(b: &bool, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    s.serialize_u8(if *b { 1 } else { 0 })
+}
+
+impl TrackerRequest {
+    pub fn create_url(&self, tracker_url: &str) -> Result<String, TrackerError> {
+        let params = serde_urlencoded::to_string(&self)?;
+
+        Ok(format!(
+            "{}?{}&info_hash={}",
+            tracker_url,
+            params,
+            Self::urlencode_bytes(&self.info_hash)
+        ))
+    }
+
+    fn urlencode_bytes(bytes: &[u8; 20]) -> String {
+        let mut encoded = String::with_capacity(3 * bytes.len());
+        for &b in bytes {
+            encoded.push('%');
+            encoded.push_str(&hex::encode(&[b]));
+        }
+
+        encoded
+    }
+
+    /// Announces to `tracker_url`, dispatching on its scheme: `http(s)://` goes
+    /// through the regular tracker HTTP GET, `udp://` speaks the BEP 15 binary
+    /// protocol over a `UdpSocket`.
+    pub fn announce(&self, tracker_url: &str) -> Result<Vec<SocketAddrV4>, TrackerError> {
+        if let Some(host_port) = tracker_url.strip_prefix("udp://") {
+            self.announce_udp(host_port.split('/').next().unwrap())
+        } else {
+            self.announce_http(tracker_url)
+        }
+    }
+
+    fn announce_http(&self, tracker_url: &str) -> Result<Vec<SocketAddrV4>, TrackerError> {
+        let url = self.create_url(tracker_url)?;
+
+        let client = reqwest::blocking::Client::new();
+        let http_response = client.get(url).send()?;
+
+        let resp: TrackerResponse = serde_bencode::from_bytes(&http_response.bytes()?)?;
+
+        Ok(resp.get_peers())
+    }
+
+    fn announce_udp(&self, host_port: &str) -> Result<Vec<SocketAddrV4>, TrackerError> {
+        let addr = host_port
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| TrackerError::AddrResolve(host_port.to_string()))?;
+
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+
+        let connection_id = self.udp_connect(&socket)?;
+        self.udp_announce(&socket, connection_id)
+    }
+
+    fn udp_connect(&self, socket: &UdpSocket) -> Result<u64, TrackerError> {
+        let transaction_id = random_u32();
+
+        let mut request = Vec::with_capacity(16);
+        request.extend_from_slice(&UDP_PROTOCOL_ID.to_be_bytes());
+        request.extend_from_slice(&UDP_ACTION_CONNECT.to_be_bytes());
+        request.extend_from_slice(&transaction_id.to_be_bytes());
+
+        let mut response = [0u8; 16];
+        udp_transact(
+            socket,
+            &request,
+            &mut response,
+            transaction_id,
+            UDP_ACTION_CONNECT,
+        )?;
+
+        Ok(u64::from_be_bytes(response[8..16].try_into().unwrap()))
+    }
+
+    fn udp_announce(
+        &self,
+        socket: &UdpSocket,
+        connection_id: u64,
+    ) -> Result<Vec<SocketAddrV4>, TrackerError> {
+        let transaction_id = random_u32();
+
+        let mut request = Vec::with_capacity(98);
+        request.extend_from_slice(&connection_id.to_be_bytes());
+        request.extend_from_slice(&UDP_ACTION_ANNOUNCE.to_be_bytes());
+        request.extend_from_slice(&transaction_id.to_be_bytes());
+        request.extend_from_slice(&self.info_hash);
+        request.extend_from_slice(self.peer_id.as_bytes());
+        request.extend_from_slice(&(self.downloaded as u64).to_be_bytes());
+        request.extend_from_slice(&(self.left as u64).to_be_bytes());
+        request.extend_from_slice(&(self.uploaded as u64).to_be_bytes());
+        request.extend_from_slice(&0u32.to_be_bytes()); // event: none
+        request.extend_from_slice(&0u32.to_be_bytes()); // ip: default
+        request.extend_from_slice(&random_u32().to_be_bytes()); // key
+        request.extend_from_slice(&(-1i32).to_be_bytes()); // num_want: default
+        request.extend_from_slice(&self.port.to_be_bytes());
+
+        let mut response = vec![0u8; 1024];
+        let n = udp_transact(
+            socket,
+            &request,
+            &mut response,
+            transaction_id,
+            UDP_ACTION_ANNOUNCE,
+        )?;
+
+        Ok(parse_udp_peers(&response[20..n]))
+    }
+}
+
+/// Sends `request` and retries with the BEP 15 `15 * 2^n` second backoff
+/// (`n` from 0 to `UDP_MAX_RETRIES`) until a response with the expected
+/// action and a matching transaction id arrives, writing it into
+/// `response` and returning its length. Responses with a stale/foreign
+/// transaction id (e.g. a straggler from a previous attempt) are ignored
+/// rather than accepted.
+fn udp_transact(
+    socket: &UdpSocket,
+    request: &[u8],
+    response: &mut [u8],
+    transaction_id: u32,
+    expected_action: u32,
+) -> Result<usize, TrackerError> {
+    for n in 0..=UDP_MAX_RETRIES {
+        socket.send(request)?;
+        socket.set_read_timeout(Some(Duration::from_secs(15 * 2u64.pow(n))))?;
+
+        match socket.recv(response) {
+            Ok(len) if len >= 8 => {
+                let action = u32::from_be_bytes(response[0..4].try_into().unwrap());
+                let received_transaction_id =
+                    u32::from_be_bytes(response[4..8].try_into().unwrap());
+
+                if action == expected_action && received_transaction_id == transaction_id {
+                    return Ok(len);
+                }
+            }
+            _ => continue,
+        }
+    }
+
+    Err(TrackerError::NoResponse)
+}
+
+fn parse_udp_peers(data: &[u8]) -> Vec<SocketAddrV4> {
+    data.chunks_exact(6)
+        .map(|p| {
+            SocketAddrV4::new(
+                Ipv4Addr::new(p[0], p[1], p[2], p[3]),
+                u16::from_be_bytes([p[4], p[5]]),
+            )
+        })
+        .collect()
+}
+
+/// A transaction id only needs to be unlikely to collide within the
+/// lifetime of a single connect/announce exchange, so we avoid pulling in
+/// a `rand` dependency and instead seed from `RandomState`'s per-process
+/// randomness. Also reused by `tfile` for shuffling announce-list tiers.
+pub(crate) fn random_u32() -> u32 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    RandomState::new().build_hasher().finish() as u32
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct TrackerResponse {
+    interval: u32,
+    #[serde(rename = "peers", with = "serde_bytes")]
+    peers_bytes: Vec<u8>,
+}
+
+impl TrackerResponse {
+    fn get_peers(&self) -> Vec<SocketAddrV4> {
+        let mut peers = vec![];
+        let n_peers = self.peers_bytes.len() / 6;
+        for i in 0..n_peers {
+            let p = &self.peers_bytes[i * 6..];
+            peers.push(SocketAddrV4::new(
+                Ipv4Addr::new(p[0], p[1], p[2], p[3]),
+                u16::from_be_bytes([p[4], p[5]]),
+            ));
+        }
+
+        peers
+    }
+}